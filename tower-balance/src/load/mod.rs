@@ -0,0 +1,162 @@
+//! Abstractions for measuring and instrumenting the load of a service.
+
+use futures::{Future, Poll};
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+pub mod peak_ewma;
+pub mod pending_requests;
+
+pub use self::peak_ewma::{PeakEwma, WithPeakEwma};
+pub use self::pending_requests::{PendingRequests, WithPendingRequests};
+
+/// Exposes a load metric for a service.
+pub trait Load {
+    /// A comparable load metric. Lesser values are assumed to correspond to
+    /// less-loaded services.
+    type Metric: PartialOrd;
+
+    fn load(&self) -> Self::Metric;
+}
+
+/// Attaches `H`-typed load information to a `V`-typed response.
+///
+/// An implementation is run on each `Service` response as it completes, so
+/// that per-request information (e.g. latency) may be folded into a
+/// service's load metric.
+pub trait Instrument<H, V> {
+    type Output;
+
+    fn instrument(handle: H, value: V) -> Self::Output;
+}
+
+/// An `Instrument` that does nothing: the handle is simply dropped and the
+/// response is returned unmodified.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoInstrument;
+
+impl<H, V> Instrument<H, V> for NoInstrument {
+    type Output = V;
+
+    fn instrument(_: H, value: V) -> V {
+        value
+    }
+}
+
+/// Wraps an `H`-typed handle with the `Instant` it was created at, so that
+/// an `Instrument` can measure end-to-end latency without the handle itself
+/// needing to know anything about timing.
+#[derive(Debug)]
+pub struct Timestamped<H> {
+    start: Instant,
+    handle: H,
+}
+
+impl<H> Timestamped<H> {
+    pub fn new(handle: H) -> Self {
+        Timestamped {
+            start: Instant::now(),
+            handle,
+        }
+    }
+
+    /// Returns how long it's been since this `Timestamped` was created.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Unwraps the `Timestamped`, discarding the captured start time.
+    pub fn into_inner(self) -> H {
+        self.handle
+    }
+}
+
+/// An `Instrument` that measures the elapsed time between when a request was
+/// issued (i.e. when its `Timestamped` handle was created, in `call()`) and
+/// when its response completed. Errors are passed through by
+/// `InstrumentFuture` without being instrumented, so no latency is reported
+/// for them.
+///
+/// This makes the `Instrument` machinery usable for latency-aware load
+/// metrics without every downstream user needing to reimplement timing by
+/// hand.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LatencyInstrument;
+
+impl<H, V> Instrument<Timestamped<H>, V> for LatencyInstrument {
+    type Output = (Duration, V);
+
+    fn instrument(handle: Timestamped<H>, value: V) -> Self::Output {
+        (handle.start.elapsed(), value)
+    }
+}
+
+/// Wraps an `H`-typed handle with the `F`-typed future producing the
+/// eventual response, attaching the handle to the response via `I` once the
+/// future completes.
+#[derive(Debug)]
+pub struct InstrumentFuture<F, I, H> {
+    future: F,
+    handle: Option<H>,
+    _p: PhantomData<fn() -> I>,
+}
+
+impl<F, I, H> InstrumentFuture<F, I, H> {
+    pub fn new(handle: H, future: F) -> Self {
+        InstrumentFuture {
+            future,
+            handle: Some(handle),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<F, I, H> Future for InstrumentFuture<F, I, H>
+where
+    F: Future,
+    I: Instrument<H, F::Item>,
+{
+    type Item = I::Output;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = try_ready!(self.future.poll());
+        let handle = self.handle.take().expect("poll called after ready");
+        Ok(I::instrument(handle, rsp).into())
+    }
+}
+
+/// Converts a `Duration` into a nanosecond count represented as `f64`.
+///
+/// This loses some precision for very large durations, which is acceptable
+/// for the load estimates this is used for.
+pub(crate) fn nanos(d: Duration) -> f64 {
+    const NANOS_PER_SEC: f64 = 1_000_000_000.0;
+    d.as_secs() as f64 * NANOS_PER_SEC + f64::from(d.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn latency_instrument_reports_elapsed_time() {
+        let (elapsed, value) = LatencyInstrument::instrument(Timestamped::new(()), "rsp");
+        assert_eq!(value, "rsp");
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn instrument_future_threads_latency_through() {
+        let handle = Timestamped::new(());
+        thread::sleep(Duration::from_millis(1));
+
+        let fut = InstrumentFuture::<_, LatencyInstrument, _>::new(handle, future::ok::<_, ()>("rsp"));
+        let (elapsed, value) = fut.wait().unwrap();
+
+        assert_eq!(value, "rsp");
+        assert!(elapsed >= Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,365 @@
+use futures::{Async, Poll};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tower_discover::{Change, Discover};
+use tower_service::Service;
+
+use super::pending_requests::RefCount;
+use super::{nanos, Instrument, InstrumentFuture, Load, NoInstrument};
+
+/// Expresses load based on a service's round-trip response latency, using
+/// the Peak EWMA algorithm: the latency estimate snaps up immediately on a
+/// new peak, and otherwise decays exponentially toward lower observed
+/// latencies, weighted by the time elapsed since the last update.
+///
+/// A small number of pending requests to a slow service may be much more
+/// costly than a larger number of pending requests to a fast service, so the
+/// estimate is weighted by `pending + 1` to account for queueing.
+#[derive(Debug)]
+pub struct PeakEwma<S, M = NoInstrument>
+where
+    S: Service,
+    M: Instrument<Handle, S::Response>,
+{
+    service: S,
+    ref_count: RefCount,
+    rtt_estimate: Arc<Mutex<RttEstimate>>,
+    tau_ns: f64,
+    _p: PhantomData<M>,
+}
+
+/// Shared between instances of `PeakEwma` and `Handle` to track a latency
+/// estimate and when it was last updated.
+#[derive(Debug)]
+struct RttEstimate {
+    update_at: Instant,
+    estimate_ns: f64,
+}
+
+/// Wraps `inner`'s services with `PeakEwma`.
+#[derive(Debug)]
+pub struct WithPeakEwma<D, M = NoInstrument>
+where
+    D: Discover,
+    M: Instrument<Handle, D::Response>,
+{
+    discover: D,
+    default_rtt: Duration,
+    tau: Duration,
+    _p: PhantomData<M>,
+}
+
+/// Represents the estimated cost of a service: its peak-EWMA latency
+/// estimate, weighted by the number of currently-pending requests.
+#[derive(Clone, Copy, Debug, Default, PartialOrd, PartialEq)]
+pub struct Cost(f64);
+
+/// Tracks an in-flight request's start time so its RTT can be recorded when
+/// it's dropped, regardless of whether the request completed, errored, or
+/// was cancelled (e.g. by a caller-side timeout or load-shedding).
+#[derive(Debug)]
+pub struct Handle {
+    start: Instant,
+    ref_count: RefCount,
+    rtt_estimate: Arc<Mutex<RttEstimate>>,
+    tau_ns: f64,
+}
+
+// ===== impl PeakEwma =====
+
+impl<S: Service> PeakEwma<S, NoInstrument> {
+    /// Creates a new `PeakEwma` estimator.
+    ///
+    /// `default_rtt` seeds the estimate so that a service that has not yet
+    /// completed a request isn't assumed to have zero latency. `tau`
+    /// controls how quickly the estimate decays back toward newly-observed,
+    /// lower latencies (i.e. it's the `tau` of the exponential decay).
+    pub fn new(service: S, default_rtt: Duration, tau: Duration) -> Self {
+        Self {
+            service,
+            ref_count: RefCount::default(),
+            rtt_estimate: Arc::new(Mutex::new(RttEstimate {
+                update_at: Instant::now(),
+                estimate_ns: nanos(default_rtt),
+            })),
+            tau_ns: nanos(tau),
+            _p: PhantomData,
+        }
+    }
+
+    /// Configures the load metric to additionally run `M` over each response
+    /// once it completes, alongside the `Handle`'s own drop-triggered RTT
+    /// recording (which happens unconditionally, so `M` isn't relied on for
+    /// correctness if a response is never reached).
+    pub fn with_instrument<M>(self) -> PeakEwma<S, M>
+    where
+        M: Instrument<Handle, S::Response>,
+    {
+        PeakEwma {
+            service: self.service,
+            ref_count: self.ref_count,
+            rtt_estimate: self.rtt_estimate,
+            tau_ns: self.tau_ns,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<S, M> PeakEwma<S, M>
+where
+    S: Service,
+    M: Instrument<Handle, S::Response>,
+{
+    fn handle(&self) -> Handle {
+        Handle {
+            start: Instant::now(),
+            ref_count: self.ref_count.clone(),
+            rtt_estimate: self.rtt_estimate.clone(),
+            tau_ns: self.tau_ns,
+        }
+    }
+}
+
+impl<S, M> Load for PeakEwma<S, M>
+where
+    S: Service,
+    M: Instrument<Handle, S::Response>,
+{
+    type Metric = Cost;
+
+    fn load(&self) -> Cost {
+        // Count the number of references that aren't `self`.
+        let pending = self.ref_count.ref_count() - 1;
+        let estimate = self.rtt_estimate.lock().expect("peak ewma lock poisoned").estimate_ns;
+        Cost(estimate * (pending + 1) as f64)
+    }
+}
+
+impl<S, M> Service for PeakEwma<S, M>
+where
+    S: Service,
+    M: Instrument<Handle, S::Response>,
+{
+    type Request = S::Request;
+    type Response = M::Output;
+    type Error = S::Error;
+    type Future = InstrumentFuture<S::Future, M, Handle>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        InstrumentFuture::new(self.handle(), self.service.call(req))
+    }
+}
+
+// ===== impl WithPeakEwma =====
+
+impl<D> WithPeakEwma<D, NoInstrument>
+where
+    D: Discover,
+{
+    pub fn new(discover: D, default_rtt: Duration, tau: Duration) -> Self {
+        Self {
+            discover,
+            default_rtt,
+            tau,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<D, M> WithPeakEwma<D, M>
+where
+    D: Discover,
+    M: Instrument<Handle, D::Response>,
+{
+    pub fn instrument<M2>(self) -> WithPeakEwma<D, M2>
+    where
+        M2: Instrument<Handle, D::Response>,
+    {
+        WithPeakEwma {
+            discover: self.discover,
+            default_rtt: self.default_rtt,
+            tau: self.tau,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<D, M> Discover for WithPeakEwma<D, M>
+where
+    D: Discover,
+    M: Instrument<Handle, D::Response>,
+{
+    type Key = D::Key;
+    type Request = D::Request;
+    type Response = M::Output;
+    type Error = D::Error;
+    type Service = PeakEwma<D::Service, M>;
+    type DiscoverError = D::DiscoverError;
+
+    /// Yields the next discovery change set.
+    fn poll(&mut self) -> Poll<Change<D::Key, Self::Service>, D::DiscoverError> {
+        use self::Change::*;
+
+        let change = match try_ready!(self.discover.poll()) {
+            Insert(k, svc) => {
+                let peak_ewma = PeakEwma::new(svc, self.default_rtt, self.tau);
+                Insert(k, peak_ewma.with_instrument())
+            }
+            Remove(k) => Remove(k),
+        };
+
+        Ok(Async::Ready(change))
+    }
+}
+
+// ==== RttEstimate ====
+
+impl RttEstimate {
+    /// Updates the estimate with a newly-observed RTT.
+    ///
+    /// Latency spikes are tracked immediately, snapping the estimate up to
+    /// the observed value; otherwise the estimate decays exponentially
+    /// toward the observed value, at a rate controlled by `tau_ns`.
+    fn update(&mut self, rtt: Duration, tau_ns: f64) {
+        let now = Instant::now();
+        let observed_ns = nanos(rtt);
+
+        self.estimate_ns = if observed_ns > self.estimate_ns {
+            observed_ns
+        } else {
+            let elapsed_ns = nanos(now.duration_since(self.update_at));
+            let w = (-elapsed_ns / tau_ns).exp();
+            self.estimate_ns * w + observed_ns * (1.0 - w)
+        };
+        self.update_at = now;
+    }
+}
+
+// ==== Handle ====
+
+impl Drop for Handle {
+    /// Records the request's RTT unconditionally on drop, whether the
+    /// response completed, errored, or the future was simply dropped (e.g.
+    /// due to a caller-side timeout or load-shedding). A backend that hangs
+    /// and is always cancelled must still show up as costly, so recording
+    /// can't be contingent on reaching `Ready`.
+    fn drop(&mut self) {
+        let rtt = self.start.elapsed();
+        let mut estimate = self.rtt_estimate.lock().expect("peak ewma lock poisoned");
+        estimate.update(rtt, self.tau_ns);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Future, Poll, future};
+    use std::thread;
+    use super::*;
+
+    struct Svc;
+    impl Service for Svc {
+        type Request = ();
+        type Response = ();
+        type Error = ();
+        type Future = future::FutureResult<(), ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            future::ok(())
+        }
+    }
+
+    #[test]
+    fn default_rtt_without_requests() {
+        let svc = PeakEwma::new(Svc, Duration::from_millis(10), Duration::from_secs(60));
+        assert_eq!(svc.load(), Cost(nanos(Duration::from_millis(10))));
+    }
+
+    #[test]
+    fn pending_requests_increase_cost() {
+        let mut svc = PeakEwma::new(Svc, Duration::from_millis(10), Duration::from_secs(60));
+        let base = svc.load();
+
+        let rsp0 = svc.call(());
+        assert!(svc.load() > base);
+
+        let rsp1 = svc.call(());
+        assert!(svc.load() > base);
+
+        let () = rsp0.wait().unwrap();
+        let () = rsp1.wait().unwrap();
+
+        // Completing a request always folds its (near-zero, but nonzero)
+        // observed RTT into the estimate, nudging it a tiny float amount away
+        // from `base` even though `pending` has returned to 0 — compare
+        // within a tolerance rather than asserting exact equality on the
+        // decayed float.
+        let Cost(base_ns) = base;
+        let Cost(after_ns) = svc.load();
+        assert!((after_ns - base_ns).abs() < nanos(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn completion_feeds_rtt_estimate() {
+        // A tiny tau constant snaps the estimate toward whatever's
+        // observed almost immediately. Starting from a much larger default
+        // RTT, a completed (near-instantaneous) request should pull the
+        // estimate sharply down, proving the Handle's drop actually folds
+        // the observed RTT into the estimate.
+        let mut svc = PeakEwma::new(Svc, Duration::from_millis(100), Duration::from_nanos(1));
+        let before = svc.load();
+
+        let () = svc.call(()).wait().unwrap();
+        assert!(svc.load() < before);
+    }
+
+    struct Pending;
+    impl Future for Pending {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<(), ()> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    struct NeverCompletes;
+    impl Service for NeverCompletes {
+        type Request = ();
+        type Response = ();
+        type Error = ();
+        type Future = Pending;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            Pending
+        }
+    }
+
+    #[test]
+    fn cancelled_request_still_feeds_rtt_estimate() {
+        // A request whose future is dropped before ever reaching `Ready` —
+        // as happens on a caller-side timeout or load-shedding — must still
+        // be recorded: a backend that hangs forever and is always cancelled
+        // should show up as costly, not as perpetually healthy.
+        let mut svc = PeakEwma::new(NeverCompletes, Duration::from_millis(100), Duration::from_nanos(1));
+        let before = svc.load();
+
+        let fut = svc.call(());
+        thread::sleep(Duration::from_millis(1));
+        drop(fut);
+
+        assert!(svc.load() < before);
+    }
+}
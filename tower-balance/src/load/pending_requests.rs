@@ -1,37 +1,49 @@
 use futures::{Async, Poll};
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tower_discover::{Change, Discover};
 use tower_service::Service;
 
-use Load;
-use super::{Instrument, InstrumentFuture, NoInstrument};
+use super::{nanos, Instrument, InstrumentFuture, Load, NoInstrument, Timestamped};
 
 /// Expresses load based on the number of currently-pending requests.
 #[derive(Debug)]
 pub struct PendingRequests<S, M = NoInstrument>
 where
     S: Service,
-    M: Instrument<Handle, S::Response>,
+    M: Instrument<Timestamped<Handle>, S::Response>,
 {
     service: S,
     ref_count: RefCount,
+    decay: Option<Mutex<Decay>>,
     _p: PhantomData<M>,
 }
 
 /// Shared between instances of `PendingRequests` and `Handle` to track active
 /// references.
 #[derive(Clone, Debug, Default)]
-struct RefCount(Arc<()>);
+pub(crate) struct RefCount(Arc<()>);
+
+/// Tracks an exponentially-weighted moving average of the pending-request
+/// count, so that bursty traffic doesn't cause `Count` to oscillate sharply
+/// between `load()` calls.
+#[derive(Debug)]
+struct Decay {
+    tau_ns: f64,
+    last_sample: Option<Instant>,
+    avg: f64,
+}
 
 /// Wraps `inner`'s services with `PendingRequests`.
 #[derive(Debug)]
 pub struct WithPendingRequests<D, M = NoInstrument>
 where
     D: Discover,
-    M: Instrument<Handle, D::Response>,
+    M: Instrument<Timestamped<Handle>, D::Response>,
 {
     discover: D,
+    decay: Option<Duration>,
     _p: PhantomData<M>,
 }
 
@@ -49,6 +61,7 @@ impl<S: Service> PendingRequests<S, NoInstrument> {
         Self {
             service,
             ref_count: RefCount::default(),
+            decay: None,
             _p: PhantomData,
         }
     }
@@ -56,11 +69,12 @@ impl<S: Service> PendingRequests<S, NoInstrument> {
     /// Configures the load metric to be determined with the provided instrumentment strategy.
     pub fn with_instrument<M>(self) -> PendingRequests<S, M>
     where
-        M: Instrument<Handle, S::Response>,
+        M: Instrument<Timestamped<Handle>, S::Response>,
     {
         PendingRequests {
             service: self.service,
             ref_count: self.ref_count,
+            decay: self.decay,
             _p: PhantomData,
         }
     }
@@ -69,8 +83,16 @@ impl<S: Service> PendingRequests<S, NoInstrument> {
 impl<S, M> PendingRequests<S, M>
 where
     S: Service,
-    M: Instrument<Handle, S::Response>,
+    M: Instrument<Timestamped<Handle>, S::Response>,
 {
+    /// Reports the pending-request count as an exponentially-weighted moving
+    /// average, decaying toward the currently-observed count at a rate
+    /// controlled by `tau`, instead of the raw, instantaneous value.
+    pub fn with_decay(mut self, tau: Duration) -> Self {
+        self.decay = Some(Mutex::new(Decay::new(tau)));
+        self
+    }
+
     fn handle(&self) -> Handle {
         Handle(self.ref_count.clone())
     }
@@ -79,32 +101,39 @@ where
 impl<S, M> Load for PendingRequests<S, M>
 where
     S: Service,
-    M: Instrument<Handle, S::Response>,
+    M: Instrument<Timestamped<Handle>, S::Response>,
 {
     type Metric = Count;
 
     fn load(&self) -> Count {
         // Count the number of references that aren't `self`.
-        Count(self.ref_count.ref_count() - 1)
+        let current = self.ref_count.ref_count() - 1;
+        match self.decay {
+            Some(ref decay) => {
+                let avg = decay.lock().expect("decay lock poisoned").update(current);
+                Count(avg)
+            }
+            None => Count(current),
+        }
     }
 }
 
 impl<S, M> Service for PendingRequests<S, M>
 where
     S: Service,
-    M: Instrument<Handle, S::Response>,
+    M: Instrument<Timestamped<Handle>, S::Response>,
 {
     type Request = S::Request;
     type Response = M::Output;
     type Error = S::Error;
-    type Future = InstrumentFuture<S::Future, M, Handle>;
+    type Future = InstrumentFuture<S::Future, M, Timestamped<Handle>>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
         self.service.poll_ready()
     }
 
     fn call(&mut self, req: Self::Request) -> Self::Future {
-        InstrumentFuture::new(self.handle(), self.service.call(req))
+        InstrumentFuture::new(Timestamped::new(self.handle()), self.service.call(req))
     }
 }
 
@@ -117,25 +146,41 @@ where
     pub fn new(discover: D) -> Self {
         Self {
             discover,
+            decay: None,
             _p: PhantomData,
         }
     }
 
     pub fn instrument<M>(self) -> WithPendingRequests<D, M>
     where
-        M: Instrument<Handle, D::Response>,
+        M: Instrument<Timestamped<Handle>, D::Response>,
     {
         WithPendingRequests {
             discover: self.discover,
+            decay: self.decay,
             _p: PhantomData,
         }
     }
 }
 
+impl<D, M> WithPendingRequests<D, M>
+where
+    D: Discover,
+    M: Instrument<Timestamped<Handle>, D::Response>,
+{
+    /// Configures discovered services to report their pending-request count
+    /// as an exponentially-weighted moving average, decaying at a rate
+    /// controlled by `tau`, rather than the raw, instantaneous value.
+    pub fn with_decay(mut self, tau: Duration) -> Self {
+        self.decay = Some(tau);
+        self
+    }
+}
+
 impl<D, M> Discover for WithPendingRequests<D, M>
 where
     D: Discover,
-    M: Instrument<Handle, D::Response>,
+    M: Instrument<Timestamped<Handle>, D::Response>,
 {
     type Key = D::Key;
     type Request = D::Request;
@@ -149,7 +194,13 @@ where
         use self::Change::*;
 
         let change = match try_ready!(self.discover.poll()) {
-            Insert(k, svc) => Insert(k, PendingRequests::new(svc).with_instrument()),
+            Insert(k, svc) => {
+                let mut pending = PendingRequests::new(svc);
+                if let Some(tau) = self.decay {
+                    pending = pending.with_decay(tau);
+                }
+                Insert(k, pending.with_instrument())
+            }
             Remove(k) => Remove(k),
         };
 
@@ -160,16 +211,49 @@ where
 // ==== RefCount ====
 
 impl RefCount {
-    pub fn ref_count(&self) -> usize {
+    pub(crate) fn ref_count(&self) -> usize {
         Arc::strong_count(&self.0)
     }
 }
 
+// ==== Decay ====
+
+impl Decay {
+    fn new(tau: Duration) -> Self {
+        Decay {
+            tau_ns: nanos(tau),
+            last_sample: None,
+            avg: 0.0,
+        }
+    }
+
+    /// Samples `current`, folding it into the moving average and returning
+    /// the updated average (rounded to the nearest pending-request count).
+    ///
+    /// The first sample bootstraps `avg` directly from `current` rather than
+    /// decaying up from an assumed value of zero, so a burst immediately
+    /// after construction isn't under-reported.
+    fn update(&mut self, current: usize) -> usize {
+        let now = Instant::now();
+        match self.last_sample {
+            None => self.avg = current as f64,
+            Some(last) => {
+                let elapsed_ns = nanos(now.duration_since(last));
+                let w = (-elapsed_ns / self.tau_ns).exp();
+                self.avg = self.avg * w + (current as f64) * (1.0 - w);
+            }
+        }
+        self.last_sample = Some(now);
+        self.avg.round() as usize
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use futures::{Future, Poll, future};
     use super::*;
+    use super::super::LatencyInstrument;
 
     struct Svc;
     impl Service for Svc {
@@ -208,10 +292,10 @@ mod tests {
     #[test]
     fn instrumented() {
         struct IntoHandle;
-        impl Instrument<Handle, ()> for IntoHandle {
+        impl Instrument<Timestamped<Handle>, ()> for IntoHandle {
             type Output = Handle;
-            fn instrument(i: Handle, (): ()) -> Handle {
-                i
+            fn instrument(i: Timestamped<Handle>, (): ()) -> Handle {
+                i.into_inner()
             }
         }
 
@@ -234,4 +318,34 @@ mod tests {
         drop(i0);
         assert_eq!(svc.load(), Count(0));
     }
+
+    #[test]
+    fn decay_converges_to_current_count() {
+        let mut svc = PendingRequests::new(Svc).with_decay(Duration::from_micros(1));
+
+        let rsp0 = svc.call(());
+        let rsp1 = svc.call(());
+        // The first sample bootstraps straight from the current count, so
+        // this doesn't need to wait for the average to catch up.
+        assert_eq!(svc.load(), Count(2));
+
+        let () = rsp0.wait().unwrap();
+        let () = rsp1.wait().unwrap();
+        // A tiny `tau` relative to this sleep means subsequent samples
+        // converge on the instantaneous count almost immediately, rather
+        // than lagging behind it the way a much larger `tau` would.
+        ::std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(svc.load(), Count(0));
+    }
+
+    #[test]
+    fn latency_instrument_reports_real_elapsed_time() {
+        let mut svc = PendingRequests::new(Svc).with_instrument::<LatencyInstrument>();
+
+        let fut = svc.call(());
+        ::std::thread::sleep(Duration::from_millis(1));
+        let (elapsed, ()) = fut.wait().unwrap();
+
+        assert!(elapsed >= Duration::from_millis(1));
+    }
 }